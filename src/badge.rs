@@ -0,0 +1,967 @@
+use std::io::Read;
+use std::time::Duration;
+
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ContentType, CACHE_CONTROL, CONTENT_TYPE, VARY,
+};
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use base64::Engine;
+use csscolorparser::parse;
+use once_cell::sync::Lazy;
+use rsbadges::{Badge, Style};
+use tokei::{Language, LanguageType};
+
+use crate::{
+    category_amount, category_label, env_var, format_bytes, FileSizes, LineDelta, Threshold,
+    Weights, AUTO_COLOR, BLUE, CATEGORIES, CHANGES, CONTENT_TYPE_SVG, DAY_IN_SECONDS, GREEN, GREY,
+    NEGOTIATED_HEADERS, RED,
+};
+
+const BILLION: usize = 1_000_000_000;
+const COMPOSITE_GAP: f64 = 4.0;
+const SPARKLINE_WIDTH: f64 = 40.0;
+const SPARKLINE_GAP: f64 = 4.0;
+const LOGO_TIMEOUT_SECS: u64 = 5;
+/// The largest logo that's embedded in badges.
+const MAX_LOGO_BYTES: u64 = 64 * 1024;
+const MILLION: usize = 1_000_000;
+const SIMPLE_ICONS_URL: &str = "https://cdn.simpleicons.org";
+const THOUSAND: usize = 1_000;
+/// The simple-icons slugs of the languages with an icon.
+const LANGUAGE_ICONS: &[(LanguageType, &str)] = &[
+    (LanguageType::Bash, "gnubash"),
+    (LanguageType::C, "c"),
+    (LanguageType::CMake, "cmake"),
+    (LanguageType::Clojure, "clojure"),
+    (LanguageType::CoffeeScript, "coffeescript"),
+    (LanguageType::Cpp, "cplusplus"),
+    (LanguageType::Crystal, "crystal"),
+    (LanguageType::Css, "css3"),
+    (LanguageType::Dart, "dart"),
+    (LanguageType::Dockerfile, "docker"),
+    (LanguageType::Elixir, "elixir"),
+    (LanguageType::Elm, "elm"),
+    (LanguageType::Erlang, "erlang"),
+    (LanguageType::FSharp, "fsharp"),
+    (LanguageType::FortranModern, "fortran"),
+    (LanguageType::Gleam, "gleam"),
+    (LanguageType::Go, "go"),
+    (LanguageType::Graphql, "graphql"),
+    (LanguageType::Groovy, "apachegroovy"),
+    (LanguageType::Haskell, "haskell"),
+    (LanguageType::Haxe, "haxe"),
+    (LanguageType::Hcl, "terraform"),
+    (LanguageType::Html, "html5"),
+    (LanguageType::Java, "openjdk"),
+    (LanguageType::JavaScript, "javascript"),
+    (LanguageType::Json, "json"),
+    (LanguageType::Jsx, "react"),
+    (LanguageType::Julia, "julia"),
+    (LanguageType::Kotlin, "kotlin"),
+    (LanguageType::Less, "less"),
+    (LanguageType::Lua, "lua"),
+    (LanguageType::Markdown, "markdown"),
+    (LanguageType::Nim, "nim"),
+    (LanguageType::Nix, "nixos"),
+    (LanguageType::OCaml, "ocaml"),
+    (LanguageType::Perl, "perl"),
+    (LanguageType::Php, "php"),
+    (LanguageType::PureScript, "purescript"),
+    (LanguageType::Python, "python"),
+    (LanguageType::R, "r"),
+    (LanguageType::Racket, "racket"),
+    (LanguageType::Ruby, "ruby"),
+    (LanguageType::Rust, "rust"),
+    (LanguageType::Sass, "sass"),
+    (LanguageType::Scala, "scala"),
+    (LanguageType::Sh, "gnubash"),
+    (LanguageType::Solidity, "solidity"),
+    (LanguageType::Svelte, "svelte"),
+    (LanguageType::Svg, "svg"),
+    (LanguageType::Swift, "swift"),
+    (LanguageType::Tex, "latex"),
+    (LanguageType::Toml, "toml"),
+    (LanguageType::Tsx, "react"),
+    (LanguageType::TypeScript, "typescript"),
+    (LanguageType::VimScript, "vim"),
+    (LanguageType::Vue, "vuedotjs"),
+    (LanguageType::Yaml, "yaml"),
+    (LanguageType::Zig, "zig"),
+];
+static SOCIAL_LOGO: Lazy<Option<String>> = Lazy::new(|| std::env::var("TOKEI_SOCIAL_LOGO").ok());
+static MINIFY_SVG: Lazy<bool> = Lazy::new(|| env_var("TOKEI_MINIFY_SVG").unwrap_or(false));
+static MAX_LABEL_LEN: Lazy<Option<usize>> = Lazy::new(|| env_var("TOKEI_MAX_LABEL_LEN"));
+static MAX_MSG_LEN: Lazy<Option<usize>> = Lazy::new(|| env_var("TOKEI_MAX_MSG_LEN"));
+pub(crate) static ERROR_STYLE: Lazy<ErrorStyle> = Lazy::new(|| ErrorStyle {
+    label: env_var("TOKEI_ERROR_LABEL"),
+    message: env_var("TOKEI_ERROR_MESSAGE").unwrap_or_else(|| "unavailable".to_owned()),
+    color: env_var("TOKEI_ERROR_COLOR").unwrap_or_else(|| RED.to_owned()),
+});
+
+fn trim_and_float(num: usize, trim: usize) -> f64 {
+    (num as f64) / (trim as f64)
+}
+
+async fn make_badge_style(
+    label: &str,
+    msg: &str,
+    color: &str,
+    label_color: &str,
+    style: &str,
+    logo: &str,
+) -> Result<String, actix_web::Error> {
+    let label: &str = &truncate(label, *MAX_LABEL_LEN);
+    let msg: &str = &truncate(msg, *MAX_MSG_LEN);
+
+    // Social badges look broken without a logo, so use the configured
+    // default or a flat badge instead.
+    let (style, logo) = match (style, logo) {
+        ("social", "") => match SOCIAL_LOGO.as_deref() {
+            Some(default_logo) => (style, default_logo),
+            None => {
+                log::warn!("No logo for social style, falling back to flat");
+                ("flat", logo)
+            }
+        },
+        _ => (style, logo),
+    };
+
+    fn badge(label: &str, msg: &str, color: &str, label_color: &str) -> Badge {
+        Badge {
+            label_text: label.to_owned(),
+            label_color: match parse(label_color) {
+                Ok(result) => result.to_hex_string(),
+                Err(_error) => GREY.to_owned(),
+            },
+            msg_text: msg.to_owned(),
+            msg_color: match parse(color) {
+                Ok(result) => result.to_hex_string(),
+                Err(_error) => BLUE.to_owned(),
+            },
+            ..Badge::default()
+        }
+    }
+
+    let badge_with_logo: Badge = Badge {
+        logo: embedded_logo(logo).unwrap_or_default(),
+        ..badge(label, msg, color, label_color)
+    };
+
+    fn stylize_badge(badge: Badge, style: &str) -> Style {
+        match style {
+            "flat" => Style::Flat(badge),
+            "flat-square" => Style::FlatSquare(badge),
+            "plastic" => Style::Plastic(badge),
+            "for-the-badge" => Style::ForTheBadge(badge),
+            "social" => Style::Social(badge),
+            _ => Style::Flat(badge),
+        }
+    }
+
+    let svg: String = match stylize_badge(badge_with_logo, style).generate_svg() {
+        Ok(s) => s,
+        Err(_e) => stylize_badge(badge(label, msg, color, label_color), style)
+            .generate_svg()
+            .unwrap(),
+    };
+
+    let aria_label: String = if label.is_empty() {
+        msg.to_owned()
+    } else {
+        format!("{}: {}", label, msg)
+    };
+    Ok(with_aria_label(&svg, &aria_label))
+}
+
+/// Renders a badge exactly `width` wide, if it's given, so that badges line
+/// up in a grid. Messages that don't fit are cut short with an ellipsis, and
+/// badges that are narrower are padded on the right.
+pub(crate) async fn make_sized_badge(
+    label: &str,
+    msg: &str,
+    color: &str,
+    label_color: &str,
+    style: &str,
+    logo: &str,
+    width: Option<f64>,
+) -> actix_web::Result<String> {
+    let badge: String = make_badge_style(label, msg, color, label_color, style, logo).await?;
+    let Some(width) = width else {
+        return Ok(badge);
+    };
+
+    let mut fitted: String = badge;
+    let characters: Vec<char> = msg.chars().collect();
+    for length in (0..characters.len()).rev() {
+        if svg_dimension(&fitted, "width").unwrap_or_default() <= width {
+            break;
+        }
+        let mut truncated: String = characters[..length].iter().collect();
+        truncated.push('…');
+        fitted = make_badge_style(label, &truncated, color, label_color, style, logo).await?;
+    }
+
+    let aria_label: String = if label.is_empty() {
+        msg.to_owned()
+    } else {
+        format!("{}: {}", label, msg)
+    };
+    Ok(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" width=\"{}\" height=\"{}\" role=\"img\" aria-label=\"{}\">{}</svg>",
+        width,
+        svg_dimension(&fitted, "height").unwrap_or_default(),
+        escape_xml(&aria_label),
+        fitted
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn make_badge(
+    stats: &Language,
+    category: &str,
+    label: &str,
+    style: &str,
+    color: &str,
+    label_color: &str,
+    logo: &str,
+    ranking_language: &str,
+    no_label: bool,
+    full_in_title: bool,
+    rounding: Option<Rounding>,
+    threshold: Option<Threshold>,
+    secondary: Option<&str>,
+    weights: &Weights,
+    file_sizes: &FileSizes,
+    width: Option<f64>,
+    partial: bool,
+) -> actix_web::Result<String> {
+    if let Some(threshold) = threshold {
+        let category: &str = threshold.category.as_deref().unwrap_or(category);
+        let amount: usize = category_amount(stats, category, weights, file_sizes);
+        let label: &str = if no_label {
+            category_label(category)
+        } else {
+            label
+        };
+        let (msg, color) = if threshold.passes(amount) {
+            ("pass", GREEN)
+        } else {
+            ("fail", RED)
+        };
+
+        let badge: String =
+            make_sized_badge(label, msg, color, label_color, style, logo, width).await?;
+        return Ok(with_title(
+            &badge,
+            &format!("{}: {} (threshold {})", label, amount, threshold.value),
+        ));
+    }
+
+    if !ranking_language.is_empty() {
+        return make_sized_badge(
+            label,
+            ranking_language,
+            color,
+            label_color,
+            style,
+            logo,
+            width,
+        )
+        .await;
+    }
+
+    let amount: usize = category_amount(stats, category, weights, file_sizes);
+    let label: &str = if no_label {
+        category_label(category)
+    } else {
+        label
+    };
+
+    let color: String = if color == AUTO_COLOR {
+        auto_color(amount)
+    } else {
+        color.to_owned()
+    };
+
+    let abbreviate = |amount: usize| match rounding {
+        Some(rounding) => abbreviate_whole(amount, rounding),
+        None => abbreviate(amount),
+    };
+    let mut msg: String = abbreviate(amount);
+    let mut aria_label: String = format!("{}: {}", label, describe(amount));
+    let mut title: String = format!("{}: {}", label, amount);
+    // A second category is shown after the first, named by the category.
+    if let Some(secondary) = secondary.filter(|secondary| CATEGORIES.contains(secondary)) {
+        let secondary_amount: usize = category_amount(stats, secondary, weights, file_sizes);
+        let secondary_msg: String = if secondary == "size" {
+            format_bytes(secondary_amount as u64, false)
+        } else {
+            abbreviate(secondary_amount)
+        };
+        msg = format!("{} · {} {}", msg, secondary_msg, secondary);
+        aria_label = format!(
+            "{}, {} {}",
+            aria_label,
+            describe_category(secondary, secondary_amount),
+            secondary
+        );
+        title = format!("{}, {} {}", title, secondary_amount, secondary);
+    }
+    // Partial counts are at least this much.
+    if partial {
+        msg.push('~');
+        aria_label.push_str(", partially counted");
+        title.push_str(" (partial)");
+    }
+
+    let badge: String =
+        make_sized_badge(label, &msg, &color, label_color, style, logo, width).await?;
+    let badge: String = with_aria_label(&badge, &aria_label);
+
+    if full_in_title {
+        Ok(with_title(&badge, &title))
+    } else {
+        Ok(badge)
+    }
+}
+
+/// Renders a badge for each of `categories` side by side in one SVG, with the
+/// logo on the first.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn make_composite_badge(
+    stats: &Language,
+    categories: &[&str],
+    style: &str,
+    color: &str,
+    label_color: &str,
+    logo: &str,
+    rounding: Option<Rounding>,
+    weights: &Weights,
+    file_sizes: &FileSizes,
+) -> actix_web::Result<String> {
+    let mut segments: String = String::new();
+    let mut aria_labels: Vec<String> = Vec::new();
+    let (mut width, mut height): (f64, f64) = (0.0, 0.0);
+    for (i, category) in categories.iter().enumerate() {
+        let amount: usize = category_amount(stats, category, weights, file_sizes);
+        let label: &str = category_label(category);
+        let color: String = if color == AUTO_COLOR {
+            auto_color(amount)
+        } else {
+            color.to_owned()
+        };
+        let msg: String = match rounding {
+            _ if *category == "size" => format_bytes(amount as u64, false),
+            Some(rounding) => abbreviate_whole(amount, rounding),
+            None => abbreviate(amount),
+        };
+        let logo: &str = if i == 0 { logo } else { "" };
+        let badge: String = make_badge_style(label, &msg, &color, label_color, style, logo).await?;
+
+        // Each badge is nested in the row, offset by the badges before it.
+        let Some((start, _)) = svg_tag(&badge) else {
+            continue;
+        };
+        if i > 0 {
+            width += COMPOSITE_GAP;
+        }
+        segments.push_str(&format!(
+            "<svg x=\"{}\"{}",
+            width,
+            &badge[start + "<svg".len()..]
+        ));
+        width += svg_dimension(&badge, "width").unwrap_or_default();
+        height = height.max(svg_dimension(&badge, "height").unwrap_or_default());
+        aria_labels.push(format!(
+            "{}: {}",
+            label,
+            describe_category(category, amount)
+        ));
+    }
+
+    Ok(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" width=\"{}\" height=\"{}\" role=\"img\" aria-label=\"{}\">{}</svg>",
+        width,
+        height,
+        escape_xml(&aria_labels.join(", ")),
+        segments
+    ))
+}
+
+/// The value of the `width` or `height` of the opening `<svg>` tag.
+pub(crate) fn svg_dimension(svg: &str, dimension: &str) -> Option<f64> {
+    svg_attribute(svg, dimension)?.parse().ok()
+}
+
+/// The value of the attribute `name` of the outermost `<svg>` tag in `svg`.
+pub(crate) fn svg_attribute<'a>(svg: &'a str, name: &str) -> Option<&'a str> {
+    let (start, end) = svg_tag(svg)?;
+    let attribute: String = format!(" {}=\"", name);
+    let value_start: usize = svg[start..end].find(&attribute)? + start + attribute.len();
+    let value_end: usize = svg[value_start..end].find('"')? + value_start;
+    Some(&svg[value_start..value_end])
+}
+
+/// Draws a sparkline of `samples` to the right of `badge`, scaled so the
+/// largest sample reaches the top of the badge.
+pub(crate) fn with_sparkline(badge: &str, samples: &[usize], color: &str) -> String {
+    let width: f64 = svg_dimension(badge, "width").unwrap_or_default();
+    let height: f64 = svg_dimension(badge, "height").unwrap_or_default();
+    let max: f64 = samples.iter().copied().max().unwrap_or_default().max(1) as f64;
+    let step: f64 = SPARKLINE_WIDTH / samples.len().saturating_sub(1).max(1) as f64;
+    let points: Vec<String> = samples
+        .iter()
+        .enumerate()
+        .map(|(index, sample)| {
+            format!(
+                "{:.1},{:.1}",
+                width + SPARKLINE_GAP + index as f64 * step,
+                height - 2.0 - *sample as f64 / max * (height - 4.0)
+            )
+        })
+        .collect();
+    let color: String = parse(color)
+        .map(|color| color.to_hex_string())
+        .unwrap_or_else(|_| BLUE.to_owned());
+
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" width=\"{}\" height=\"{}\" role=\"img\" aria-label=\"{}\">{}<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/></svg>",
+        width + SPARKLINE_GAP + SPARKLINE_WIDTH,
+        height,
+        svg_attribute(badge, "aria-label").unwrap_or_default(),
+        badge,
+        color,
+        points.join(" ")
+    )
+}
+
+/// The URL of the simple-icons logo for `language_type`, if it has one.
+pub(crate) fn language_icon(language_type: LanguageType) -> Option<String> {
+    LANGUAGE_ICONS
+        .iter()
+        .find(|(icon_language_type, _)| *icon_language_type == language_type)
+        .map(|(_, slug)| format!("{}/{}", SIMPLE_ICONS_URL, slug))
+}
+
+/// `logo` as a data URI that's embedded in badges, downloading it if it's an
+/// `https://` URL. Logos are cached, as the same few language icons are in
+/// most badges, and anything else, such as a path, isn't embedded.
+#[cached::proc_macro::cached(
+    name = "LOGO_CACHE",
+    type = "cached::TimedSizedCache<String, Option<String>>",
+    create = "{ cached::TimedSizedCache::with_size_and_lifespan(1000, DAY_IN_SECONDS) }",
+    convert = r#"{ logo.to_owned() }"#
+)]
+pub(crate) fn embedded_logo(logo: &str) -> Option<String> {
+    if logo.starts_with("data:") {
+        return Some(logo.to_owned());
+    }
+    if !logo.starts_with("https://") {
+        return None;
+    }
+
+    let response: ureq::Response = match ureq::get(logo)
+        .timeout(Duration::from_secs(LOGO_TIMEOUT_SECS))
+        .call()
+    {
+        Ok(response) => response,
+        Err(error) => {
+            log::warn!("{} - Couldn't download logo: {}", logo, error);
+            return None;
+        }
+    };
+    let content_type: String = response.content_type().to_owned();
+    let mut data: Vec<u8> = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_LOGO_BYTES + 1)
+        .read_to_end(&mut data)
+        .ok()?;
+    if data.len() as u64 > MAX_LOGO_BYTES {
+        log::warn!("{} - Logo is larger than {} bytes", logo, MAX_LOGO_BYTES);
+        return None;
+    }
+
+    let data_uri: Option<String> = logo_data_uri(&content_type, &data);
+    if data_uri.is_none() {
+        log::warn!("{} - Logo is `{}`, not an image", logo, content_type);
+    }
+    data_uri
+}
+
+/// A data URI of the logo `data` served as `content_type`, if it's an image.
+fn logo_data_uri(content_type: &str, data: &[u8]) -> Option<String> {
+    let subtype: &str = content_type.strip_prefix("image/")?;
+    let is_token: bool = !subtype.is_empty()
+        && subtype
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    if !is_token {
+        return None;
+    }
+
+    Some(format!(
+        "data:{};base64,{}",
+        content_type,
+        base64::engine::general_purpose::STANDARD.encode(data)
+    ))
+}
+
+/// Adds a `<title>` to `svg`, which is shown as a tooltip when hovering over
+/// the badge.
+pub(crate) fn with_title(svg: &str, title: &str) -> String {
+    let Some((_, end_of_svg_tag)) = svg_tag(svg) else {
+        return svg.to_owned();
+    };
+
+    format!(
+        "{}<title>{}</title>{}",
+        &svg[..end_of_svg_tag],
+        escape_xml(title),
+        &svg[end_of_svg_tag..]
+    )
+}
+
+/// Labels `svg` for screen readers, replacing the `aria-label` rsbadges
+/// generates, which only describes the style.
+fn with_aria_label(svg: &str, aria_label: &str) -> String {
+    let Some((start, end)) = svg_tag(svg) else {
+        return svg.to_owned();
+    };
+
+    let mut tag: String = svg[start..end - 1].to_owned();
+    for attribute in [" role=\"", " aria-label=\""] {
+        if let Some(attribute_start) = tag.find(attribute) {
+            let value_start: usize = attribute_start + attribute.len();
+            if let Some(value_length) = tag[value_start..].find('"') {
+                tag.replace_range(attribute_start..value_start + value_length + 1, "");
+            }
+        }
+    }
+
+    format!(
+        "{}{} role=\"img\" aria-label=\"{}\">{}",
+        &svg[..start],
+        tag,
+        escape_xml(aria_label),
+        &svg[end..]
+    )
+}
+
+/// Finds the start and end of the opening `<svg>` tag.
+fn svg_tag(svg: &str) -> Option<(usize, usize)> {
+    let start: usize = svg.find("<svg")?;
+    let end: usize = svg[start..].find('>')? + start + 1;
+    Some((start, end))
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Shortens `text` to `max_len` characters, ending it with an ellipsis, so
+/// that long labels can't make badges arbitrarily wide.
+pub(crate) fn truncate(text: &str, max_len: Option<usize>) -> String {
+    match max_len {
+        Some(max_len) if text.chars().count() > max_len => {
+            log::info!("Truncating badge text to {} characters", max_len);
+            let mut truncated: String = text.chars().take(max_len.saturating_sub(1)).collect();
+            truncated.push('…');
+            truncated
+        }
+        _ => text.to_owned(),
+    }
+}
+
+/// How error badges look, from `TOKEI_ERROR_LABEL`, `TOKEI_ERROR_MESSAGE`
+/// and `TOKEI_ERROR_COLOR`.
+pub(crate) struct ErrorStyle {
+    /// Replaces the badge's own label.
+    label: Option<String>,
+    message: String,
+    color: String,
+}
+
+/// Renders an error as a badge so embedded images show an "unavailable" or
+/// "rate limited" state instead of a broken image. The status is kept.
+pub(crate) async fn error_badge(
+    status: StatusCode,
+    error_style: &ErrorStyle,
+    label: &str,
+    style: &str,
+    label_color: &str,
+    logo: &str,
+) -> actix_web::Result<HttpResponse> {
+    let label: &str = error_style.label.as_deref().unwrap_or(label);
+    let msg: &str = if status == StatusCode::TOO_MANY_REQUESTS {
+        "rate limited"
+    } else {
+        &error_style.message
+    };
+    let badge: String =
+        make_badge_style(label, msg, &error_style.color, label_color, style, logo).await?;
+
+    Ok(HttpResponse::build(status)
+        .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+        .insert_header((VARY, NEGOTIATED_HEADERS))
+        .insert_header((CONTENT_TYPE, CONTENT_TYPE_SVG.clone()))
+        .body(minify_svg(badge)))
+}
+
+/// Strips the comments from `body`, if it's an SVG and `TOKEI_MINIFY_SVG` is
+/// set, along with the whitespace between its elements and attributes. Text
+/// and attribute values are left as they are, so it renders the same, and the
+/// same badge is always minified to the same bytes.
+pub(crate) fn minify_svg(body: String) -> String {
+    if !*MINIFY_SVG || !body.starts_with("<svg") {
+        return body;
+    }
+
+    minified_svg(&body)
+}
+
+/// `svg` without its comments, or the whitespace between its elements and
+/// attributes.
+fn minified_svg(svg: &str) -> String {
+    let mut without_comments: String = String::with_capacity(svg.len());
+    let mut rest: &str = svg;
+    while let Some(start) = rest.find("<!--") {
+        without_comments.push_str(&rest[..start]);
+        rest = rest[start..]
+            .find("-->")
+            .map_or("", |end| &rest[start + end + 3..]);
+    }
+    without_comments.push_str(rest);
+
+    let mut minified: String = String::with_capacity(without_comments.len());
+    // Text between elements, which is dropped if it's only whitespace.
+    let mut text: String = String::new();
+    let mut in_tag: bool = false;
+    let mut quote: Option<char> = None;
+    let mut space: bool = false;
+    for c in without_comments.chars() {
+        if let Some(open) = quote {
+            minified.push(c);
+            if c == open {
+                quote = None;
+            }
+        } else if !in_tag {
+            if c == '<' {
+                if !text.trim().is_empty() {
+                    minified.push_str(&text);
+                }
+                text.clear();
+                minified.push(c);
+                in_tag = true;
+            } else {
+                text.push(c);
+            }
+        } else if c.is_whitespace() {
+            space = true;
+        } else {
+            // Whitespace before the end of a tag isn't needed.
+            if space && !matches!(c, '>' | '/') {
+                minified.push(' ');
+            }
+            space = false;
+            minified.push(c);
+            match c {
+                '"' | '\'' => quote = Some(c),
+                '>' => in_tag = false,
+                _ => {}
+            }
+        }
+    }
+    if !text.trim().is_empty() {
+        minified.push_str(&text);
+    }
+
+    minified
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn make_delta_badge(
+    content_type: &ContentType,
+    delta: &LineDelta,
+    label: &str,
+    style: &str,
+    color: &str,
+    label_color: &str,
+    logo: &str,
+    no_label: bool,
+) -> actix_web::Result<String> {
+    if *content_type == ContentType::json() {
+        return Ok(serde_json::to_string(&delta)?);
+    }
+
+    let label: &str = if no_label { CHANGES } else { label };
+    let color: String = if color == AUTO_COLOR {
+        auto_color(delta.added + delta.removed)
+    } else {
+        color.to_owned()
+    };
+    let msg = format!(
+        "+{}/-{}",
+        abbreviate(delta.added),
+        abbreviate(delta.removed)
+    );
+
+    make_badge_style(label, &msg, &color, label_color, style, logo).await
+}
+
+fn abbreviate(amount: usize) -> String {
+    if amount >= BILLION {
+        format!("{:.1}B", trim_and_float(amount, BILLION))
+    } else if amount >= MILLION {
+        format!("{:.1}M", trim_and_float(amount, MILLION))
+    } else if amount >= THOUSAND {
+        format!("{:.1}K", trim_and_float(amount, THOUSAND))
+    } else {
+        amount.to_string()
+    }
+}
+
+/// Spells out `amount` of `category`, which for `size` is in bytes.
+fn describe_category(category: &str, amount: usize) -> String {
+    if category == "size" {
+        format_bytes(amount as u64, false)
+    } else {
+        describe(amount)
+    }
+}
+
+/// Spells out the abbreviation of `amount`, e.g. `1.2 million`.
+fn describe(amount: usize) -> String {
+    if amount >= BILLION {
+        format!("{:.1} billion", trim_and_float(amount, BILLION))
+    } else if amount >= MILLION {
+        format!("{:.1} million", trim_and_float(amount, MILLION))
+    } else if amount >= THOUSAND {
+        format!("{:.1} thousand", trim_and_float(amount, THOUSAND))
+    } else {
+        amount.to_string()
+    }
+}
+
+/// How to round an abbreviation to a whole unit.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Rounding {
+    Nearest,
+    Down,
+    Up,
+}
+
+impl Rounding {
+    /// Rounds `amount` to a multiple of `step`.
+    fn round(self, amount: usize, step: usize) -> usize {
+        match self {
+            Rounding::Nearest => (amount + step / 2) / step * step,
+            Rounding::Down => amount / step * step,
+            Rounding::Up => amount.div_ceil(step) * step,
+        }
+    }
+}
+
+/// Abbreviates `amount` to a whole unit, e.g. `12K` or `3B`.
+fn abbreviate_whole(amount: usize, rounding: Rounding) -> String {
+    for (unit, suffix) in [(BILLION, "B"), (MILLION, "M"), (THOUSAND, "K")] {
+        // Use the larger unit when rounding would carry into it, so 999,600
+        // is `1M` rather than `1000K`.
+        if rounding.round(amount, unit / THOUSAND) >= unit {
+            return format!("{}{}", rounding.round(amount, unit) / unit, suffix);
+        }
+    }
+
+    amount.to_string()
+}
+
+/// Picks a color between blue and green for `amount`, on a log scale where
+/// anything from a million upwards is fully green.
+fn auto_color(amount: usize) -> String {
+    let magnitude: f64 = (amount.max(1) as f64).log10() / (MILLION as f64).log10();
+    let blue = parse(BLUE).unwrap();
+    let green = parse(GREEN).unwrap();
+
+    blue.interpolate_rgb(&green, magnitude.min(1.0))
+        .to_hex_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use cached::Cached;
+
+    use super::*;
+
+    #[test]
+    fn minified_svg_keeps_text_and_values() {
+        let svg: &str = "<svg width=\"10\"  xmlns=\"x\" >\n  <!-- a comment -->\n  <title>lines:  1.2K</title>\n  <text x=\"1\"\n    fill='#fff' >a  b</text>\n  <rect  width=\"5\" />\n</svg>\n";
+
+        assert_eq!(
+            minified_svg(svg),
+            "<svg width=\"10\" xmlns=\"x\"><title>lines:  1.2K</title><text x=\"1\" fill='#fff'>a  b</text><rect width=\"5\"/></svg>"
+        );
+        assert_eq!(minified_svg(&minified_svg(svg)), minified_svg(svg));
+    }
+
+    #[test]
+    fn compact_abbreviates_to_whole_units() {
+        let compact = |amount: usize| abbreviate_whole(amount, Rounding::Nearest);
+
+        assert_eq!(compact(999), "999");
+        assert_eq!(compact(1_000), "1K");
+        assert_eq!(compact(1_499), "1K");
+        assert_eq!(compact(1_500), "2K");
+        assert_eq!(compact(999_499), "999K");
+        assert_eq!(compact(999_600), "1M");
+        assert_eq!(compact(2_500_000_000), "3B");
+    }
+
+    #[test]
+    fn round_modes() {
+        assert_eq!(abbreviate_whole(1_200, Rounding::Down), "1K");
+        assert_eq!(abbreviate_whole(1_200, Rounding::Up), "2K");
+        assert_eq!(abbreviate_whole(1_000, Rounding::Up), "1K");
+        assert_eq!(abbreviate_whole(999_999, Rounding::Down), "999K");
+        assert_eq!(abbreviate_whole(999_001, Rounding::Up), "1M");
+        assert_eq!(abbreviate_whole(1_999_999, Rounding::Down), "1M");
+        assert_eq!(abbreviate_whole(12, Rounding::Up), "12");
+    }
+
+    #[test]
+    fn logos_keep_their_image_type() {
+        assert_eq!(
+            logo_data_uri("image/svg+xml", b"<svg></svg>").unwrap(),
+            "data:image/svg+xml;base64,PHN2Zz48L3N2Zz4="
+        );
+        assert_eq!(
+            logo_data_uri("image/png", &[0x89, b'P', b'N', b'G']).unwrap(),
+            "data:image/png;base64,iVBORw=="
+        );
+        assert_eq!(logo_data_uri("text/html", b"<html></html>"), None);
+        assert_eq!(logo_data_uri("image/", b""), None);
+        assert_eq!(logo_data_uri("image/png;base64,", b""), None);
+    }
+
+    #[actix_web::test]
+    async fn rust_badge_embeds_its_logo() {
+        let icon: String = language_icon(LanguageType::Rust).unwrap();
+        assert_eq!(icon, "https://cdn.simpleicons.org/rust");
+        let data_uri: &str = "data:image/svg+xml;base64,PHN2Zz48L3N2Zz4=";
+        LOGO_CACHE
+            .lock()
+            .unwrap()
+            .cache_set(icon.clone(), Some(data_uri.to_owned()));
+
+        let badge: String = make_badge_style("code", "1K", BLUE, GREY, "flat", &icon)
+            .await
+            .unwrap();
+        assert!(badge.contains(data_uri));
+        assert!(!badge.contains(&icon));
+
+        assert_eq!(embedded_logo("/etc/passwd"), None);
+    }
+
+    #[test]
+    fn auto_color_goes_from_blue_to_green() {
+        let (blue, green) = (parse(BLUE).unwrap(), parse(GREEN).unwrap());
+        assert_eq!(auto_color(0), blue.to_hex_string());
+        assert_eq!(auto_color(1), blue.to_hex_string());
+        assert_eq!(
+            auto_color(1000),
+            blue.interpolate_rgb(&green, 0.5).to_hex_string()
+        );
+        assert_eq!(auto_color(1_000_000), green.to_hex_string());
+        assert_eq!(auto_color(1_000_000_000), green.to_hex_string());
+
+        // Bigger amounts are ever greener.
+        let green_of = |amount: usize| parse(&auto_color(amount)).unwrap().g;
+        assert!(green_of(10) < green_of(10_000));
+        assert!(green_of(10_000) < green_of(100_000));
+    }
+
+    #[actix_web::test]
+    async fn social_badges_without_a_logo_fall_back_to_flat() {
+        let logo: &str = "data:image/svg+xml;base64,PHN2Zz48L3N2Zz4=";
+        let badge = |style: &'static str, logo: &'static str| {
+            make_badge_style("code", "1K", BLUE, GREY, style, logo)
+        };
+
+        // Social badges are the only ones with `#llink`, which GitHub's
+        // styles highlight on hover.
+        let social: String = badge("social", logo).await.unwrap();
+        assert!(social.contains(logo));
+        assert!(social.contains(r#"id="llink""#));
+
+        // `TOKEI_SOCIAL_LOGO` isn't set.
+        let fallback: String = badge("social", "").await.unwrap();
+        assert!(!fallback.contains(r#"id="llink""#));
+        assert!(fallback.contains(">code</text>"));
+    }
+
+    #[actix_web::test]
+    async fn label_color_colors_the_label() {
+        let badge: String = make_badge_style("code", "1K", BLUE, "#ff8800", "flat", "")
+            .await
+            .unwrap();
+        assert!(badge.contains(r#"fill="rgb(255, 136, 0)""#), "{}", badge);
+        assert!(badge.contains(r#"fill="rgb(0, 126, 198)""#), "{}", badge);
+
+        // Invalid colors leave the label grey.
+        let badge: String = make_badge_style("code", "1K", BLUE, "not a color", "flat", "")
+            .await
+            .unwrap();
+        assert!(badge.contains(r#"fill="rgb(85, 85, 85)""#), "{}", badge);
+    }
+
+    #[actix_web::test]
+    async fn error_badges_use_the_custom_style() {
+        async fn render(status: StatusCode, error_style: &ErrorStyle) -> (StatusCode, String) {
+            let response: HttpResponse =
+                error_badge(status, error_style, "lines of code", "flat", "#555", "")
+                    .await
+                    .unwrap();
+            let status: StatusCode = response.status();
+            let body = actix_web::body::to_bytes(response.into_body())
+                .await
+                .unwrap();
+            (status, String::from_utf8_lossy(&body).into_owned())
+        }
+        let default_style = ErrorStyle {
+            label: None,
+            message: "unavailable".to_owned(),
+            color: RED.to_owned(),
+        };
+        let custom_style = ErrorStyle {
+            label: Some("example.com".to_owned()),
+            message: "down for maintenance".to_owned(),
+            color: "663399".to_owned(),
+        };
+
+        let (status, svg) = render(StatusCode::SERVICE_UNAVAILABLE, &default_style).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(svg.contains(">lines of code<") && svg.contains(">unavailable<"));
+
+        let (status, svg) = render(StatusCode::SERVICE_UNAVAILABLE, &custom_style).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(svg.contains(">example.com<"), "{}", svg);
+        assert!(svg.contains(">down for maintenance<"), "{}", svg);
+        assert!(svg.contains("rgb(102, 51, 153)"), "{}", svg);
+
+        // Rate limits still say so.
+        let (_, svg) = render(StatusCode::TOO_MANY_REQUESTS, &custom_style).await;
+        assert!(svg.contains(">rate limited<"), "{}", svg);
+    }
+
+    #[test]
+    fn over_long_text_is_truncated() {
+        assert_eq!(truncate("lines of code", None), "lines of code");
+        assert_eq!(truncate("lines of code", Some(13)), "lines of code");
+        assert_eq!(truncate("lines of code", Some(6)), "lines…");
+        assert_eq!(truncate("コードの行数", Some(4)), "コード…");
+        assert_eq!(truncate("lines", Some(1)), "…");
+    }
+}
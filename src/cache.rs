@@ -0,0 +1,509 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use cached::{Cached, SizedCache};
+use once_cell::sync::Lazy;
+
+use crate::badge::LOGO_CACHE;
+use crate::error::{CodedError, ErrorCode};
+use crate::git::CLONED_REFS_CACHE;
+use crate::{
+    env_var, Statistics, AS_OF_CACHE, AUTHOR_CACHE, BRANCH_DELTA_CACHE, BUSY_RETRY_AFTER_SECS,
+    DAY_IN_SECONDS, DELTA_CACHE, RESOLVED_ETAGS, TREND_CACHE,
+};
+
+const CACHE_LIFESPAN: Duration = Duration::from_secs(DAY_IN_SECONDS);
+pub(crate) const CACHE_SIZE: usize = 1000;
+static CACHE_MAX_BYTES: Lazy<Option<usize>> = Lazy::new(|| env_var("TOKEI_CACHE_MAX_BYTES"));
+static CACHE_JITTER_PERCENT: Lazy<u64> =
+    Lazy::new(|| env_var("TOKEI_CACHE_JITTER_PERCENT").unwrap_or(10).min(100));
+pub(crate) static CACHE: Lazy<Mutex<SizedCache<String, CachedStatistics>>> =
+    Lazy::new(|| Mutex::new(SizedCache::with_size(CACHE_SIZE)));
+pub(crate) static REFRESHING: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+/// The key of the latest statistics counted for each `branch_identifier`.
+pub(crate) static LATEST_KEYS: Lazy<Mutex<SizedCache<String, String>>> =
+    Lazy::new(|| Mutex::new(SizedCache::with_size(CACHE_SIZE)));
+static REPO_COUNTS: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Default::default);
+
+/// Removes expired entries from the caches, which otherwise keep them until
+/// they're requested again or pushed out by newer ones. Each cache is only
+/// locked while it's swept, so requests are never held up for long.
+pub(crate) fn sweep_caches() {
+    let mut swept: usize = 0;
+    {
+        // Stale statistics are still served while they're recounted, so
+        // they're only dropped once they're well past their lifespan.
+        let mut cache = CACHE.lock().unwrap();
+        let before: usize = cache.cache_size();
+        cache.retain(|_, cached| cached.counted_at.elapsed() < cached.lifespan * 2);
+        swept += before - cache.cache_size();
+    }
+    swept += flush(&RESOLVED_ETAGS);
+    swept += flush(&DELTA_CACHE);
+    swept += flush(&BRANCH_DELTA_CACHE);
+    swept += flush(&AS_OF_CACHE);
+    swept += flush(&AUTHOR_CACHE);
+    swept += flush(&TREND_CACHE);
+    swept += flush(&CLONED_REFS_CACHE);
+    swept += flush(&LOGO_CACHE);
+    if swept > 0 {
+        log::info!("Swept {} expired cache entries", swept);
+    }
+}
+
+/// Removes the expired entries of `cache`, returning how many there were.
+fn flush<K, V>(cache: &Mutex<cached::TimedSizedCache<K, V>>) -> usize
+where
+    K: std::hash::Hash + Eq + Clone,
+{
+    let mut cache = cache.lock().unwrap();
+    let before: usize = cache.cache_size();
+    cache.flush();
+    before - cache.cache_size()
+}
+
+/// Statistics of a repository, kept after their lifespan so they can still be
+/// served while they're recounted.
+pub(crate) struct CachedStatistics {
+    statistics: Statistics,
+    counted_at: Instant,
+    /// `CACHE_LIFESPAN`, give or take `TOKEI_CACHE_JITTER_PERCENT`, so that
+    /// statistics counted together aren't all recounted together too.
+    lifespan: Duration,
+    /// Roughly how many bytes `statistics` takes up.
+    size: usize,
+}
+
+impl CachedStatistics {
+    fn new(statistics: Statistics) -> Self {
+        let jitter: u64 = CACHE_LIFESPAN.as_secs() * *CACHE_JITTER_PERCENT / 100;
+        // Every `RandomState` is seeded differently, so it's a source of
+        // randomness without another dependency.
+        let random: u64 = RandomState::new().build_hasher().finish();
+        CachedStatistics {
+            size: statistics.size(),
+            statistics,
+            counted_at: Instant::now(),
+            lifespan: Duration::from_secs(
+                CACHE_LIFESPAN.as_secs() - jitter + random % (2 * jitter + 1),
+            ),
+        }
+    }
+
+    pub(crate) fn is_stale(&self) -> bool {
+        self.counted_at.elapsed() > self.lifespan
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CacheStatus {
+    Hit,
+    Miss,
+    /// The statistics are older than their lifespan, and are being
+    /// recounted in the background.
+    Stale,
+    /// Counting failed, so the statistics of an earlier commit of the same
+    /// branch were returned instead, as `TOKEI_SERVE_STALE_ON_ERROR` is set.
+    StaleError,
+}
+
+/// Gets the statistics cached under `key`, using `count` to count them if
+/// they aren't cached. Counts of `repo` beyond `max_counts` fail as busy, or
+/// keep serving stale statistics, until another finishes. If
+/// counting fails, the latest statistics cached for `branch_key` are returned
+/// instead when `serve_stale_on_error` is set.
+pub(crate) fn get_statistics<F>(
+    key: String,
+    branch_key: Option<String>,
+    repo: &str,
+    max_counts: Option<usize>,
+    serve_stale_on_error: bool,
+    count: F,
+) -> eyre::Result<(Statistics, CacheStatus)>
+where
+    F: FnOnce() -> eyre::Result<Statistics> + Send + 'static,
+{
+    let cached = CACHE
+        .lock()
+        .unwrap()
+        .cache_get(&key)
+        .map(|cached| (cached.statistics.clone(), cached.is_stale()));
+
+    match cached {
+        Some((statistics, false)) => Ok((statistics, CacheStatus::Hit)),
+        Some((statistics, true)) => {
+            match RepoSlot::acquire(repo, max_counts) {
+                Some(slot) => refresh_statistics(key, slot, count),
+                None => log::info!("{} - Too many counts of {}, not refreshing", key, repo),
+            }
+            Ok((statistics, CacheStatus::Stale))
+        }
+        None => {
+            let Some(_slot) = RepoSlot::acquire(repo, max_counts) else {
+                return Err(CodedError::new(
+                    ErrorCode::Busy,
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("{} is already being counted, try again later.", repo),
+                )
+                .with_retry_after(BUSY_RETRY_AFTER_SECS)
+                .into());
+            };
+            let statistics = match count() {
+                Ok(statistics) => statistics,
+                Err(error) => {
+                    let latest: Option<Statistics> = branch_key
+                        .filter(|_| serve_stale_on_error)
+                        .and_then(|branch_key| {
+                            LATEST_KEYS.lock().unwrap().cache_get(&branch_key).cloned()
+                        })
+                        .and_then(|latest_key| {
+                            CACHE
+                                .lock()
+                                .unwrap()
+                                .cache_get(&latest_key)
+                                .map(|cached| cached.statistics.clone())
+                        });
+                    return match latest {
+                        Some(statistics) => {
+                            log::warn!("{} - Couldn't count: {}", key, error);
+                            Ok((statistics, CacheStatus::StaleError))
+                        }
+                        None => Err(error),
+                    };
+                }
+            };
+            if let Some(branch_key) = branch_key.filter(|_| !statistics.partial) {
+                LATEST_KEYS
+                    .lock()
+                    .unwrap()
+                    .cache_set(branch_key, key.clone());
+            }
+            cache_statistics(key, statistics.clone());
+            Ok((statistics, CacheStatus::Miss))
+        }
+    }
+}
+
+/// Recounts the statistics cached under `key` on a background thread, unless
+/// they're already being recounted, holding `slot` until it's done.
+fn refresh_statistics<F>(key: String, slot: RepoSlot, count: F)
+where
+    F: FnOnce() -> eyre::Result<Statistics> + Send + 'static,
+{
+    if !REFRESHING.lock().unwrap().insert(key.clone()) {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        log::info!("{} - Refreshing", key);
+        match count() {
+            Ok(statistics) => cache_statistics(key.clone(), statistics),
+            Err(error) => log::warn!("{} - Couldn't refresh: {}", key, error),
+        }
+        REFRESHING.lock().unwrap().remove(&key);
+        drop(slot);
+    });
+}
+
+/// One of the `TOKEI_MAX_COUNTS_PER_REPO` counts of a repository that may
+/// run at once, so that a popular repository can't take up every worker.
+/// Counts of the same statistics are already coalesced, so this limits
+/// counts of its other branches and options.
+struct RepoSlot(String);
+
+impl RepoSlot {
+    fn acquire(repo: &str, max: Option<usize>) -> Option<RepoSlot> {
+        let mut counts = REPO_COUNTS.lock().unwrap();
+        let count: &mut usize = counts.entry(repo.to_owned()).or_default();
+        if max.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+
+        Some(RepoSlot(repo.to_owned()))
+    }
+}
+
+impl Drop for RepoSlot {
+    fn drop(&mut self) {
+        let mut counts = REPO_COUNTS.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.0);
+            }
+        }
+    }
+}
+
+pub(crate) fn cache_statistics(key: String, statistics: Statistics) {
+    // Partial statistics are counted again by the next request instead.
+    if statistics.partial {
+        return;
+    }
+    let mut cache = CACHE.lock().unwrap();
+    cache.cache_set(key, CachedStatistics::new(statistics));
+    if let Some(max_bytes) = *CACHE_MAX_BYTES {
+        evict_to_fit(&mut cache, max_bytes);
+    }
+}
+
+/// Evicts the least recently used statistics from `cache` until they take up
+/// at most `max_bytes`, as the reports of a few large repositories can take
+/// up more memory than many small ones. The most recently used statistics are
+/// always kept.
+fn evict_to_fit(cache: &mut SizedCache<String, CachedStatistics>, max_bytes: usize) {
+    let mut size: usize = cache.value_order().map(|cached| cached.size).sum();
+    while size > max_bytes && cache.cache_size() > 1 {
+        let Some(key) = cache.key_order().last().cloned() else {
+            break;
+        };
+        if let Some(evicted) = cache.cache_remove(&key) {
+            log::info!("{} - Evicted {} bytes", key, evicted.size);
+            size -= evicted.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use actix_web::http::header::RETRY_AFTER;
+    use tokei::{Language, LanguageType};
+
+    use super::*;
+    use crate::{branch_identifier, CountOptions};
+
+    #[test]
+    fn serves_latest_statistics_when_counting_fails() {
+        let repo: &str = "https://example.com/u/stale";
+        let branch_key: String = branch_identifier(repo, "", "", &CountOptions::default());
+
+        let counted = Statistics {
+            skipped: 42,
+            ..Statistics::default()
+        };
+        let (statistics, status) = get_statistics(
+            format!("{}#first", repo),
+            Some(branch_key.clone()),
+            repo,
+            None,
+            true,
+            move || Ok(counted),
+        )
+        .unwrap();
+        assert_eq!((statistics.skipped, status), (42, CacheStatus::Miss));
+
+        // A newer commit that can't be cloned, or the repository being
+        // unreachable altogether, serves what was counted before.
+        for key in ["second", "unreachable"] {
+            let (statistics, status) = get_statistics(
+                format!("{}#{}", repo, key),
+                Some(branch_key.clone()),
+                repo,
+                None,
+                true,
+                || Err(eyre::eyre!("Couldn't clone.")),
+            )
+            .unwrap();
+            assert_eq!((statistics.skipped, status), (42, CacheStatus::StaleError));
+        }
+        // Unless stale statistics aren't served on errors.
+        assert!(get_statistics(
+            format!("{}#third", repo),
+            Some(branch_key.clone()),
+            repo,
+            None,
+            false,
+            || Err(eyre::eyre!("Couldn't clone.")),
+        )
+        .is_err());
+
+        let other_branch: String = branch_identifier(repo, "other", "", &CountOptions::default());
+        assert!(get_statistics(
+            format!("{}#other", repo),
+            Some(other_branch),
+            repo,
+            None,
+            true,
+            || Err(eyre::eyre!("Couldn't clone.")),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn repo_slots_are_per_repository() {
+        let first = RepoSlot::acquire("https://example.com/u/busy", Some(1)).unwrap();
+        assert!(RepoSlot::acquire("https://example.com/u/busy", Some(1)).is_none());
+        assert!(RepoSlot::acquire("https://example.com/u/idle", Some(1)).is_some());
+
+        drop(first);
+        assert!(RepoSlot::acquire("https://example.com/u/busy", Some(1)).is_some());
+        assert!(RepoSlot::acquire("https://example.com/u/busy", None).is_some());
+    }
+
+    #[test]
+    fn busy_repository_does_not_block_another() {
+        let busy: &str = "https://example.com/u/counting";
+        let (started_sender, started) = std::sync::mpsc::channel();
+        let (finish, finish_receiver) = std::sync::mpsc::channel::<()>();
+        let counting = std::thread::spawn(move || {
+            get_statistics(
+                format!("{}#main", busy),
+                None,
+                busy,
+                Some(1),
+                false,
+                move || {
+                    started_sender.send(()).unwrap();
+                    finish_receiver.recv().unwrap();
+                    Ok(Statistics::default())
+                },
+            )
+        });
+        started.recv().unwrap();
+
+        let error = get_statistics(
+            format!("{}#other", busy),
+            None,
+            busy,
+            Some(1),
+            false,
+            || Ok(Statistics::default()),
+        )
+        .unwrap_err();
+        let error: &CodedError = error.downcast_ref().unwrap();
+        assert_eq!(error.status, StatusCode::SERVICE_UNAVAILABLE);
+        let response = actix_web::ResponseError::error_response(error);
+        assert_eq!(
+            response.headers().get(RETRY_AFTER).unwrap(),
+            &BUSY_RETRY_AFTER_SECS.to_string()
+        );
+
+        let idle: &str = "https://example.com/u/not-counting";
+        let (_, status) =
+            get_statistics(format!("{}#main", idle), None, idle, Some(1), false, || {
+                Ok(Statistics::default())
+            })
+            .unwrap();
+        assert_eq!(status, CacheStatus::Miss);
+
+        finish.send(()).unwrap();
+        assert_eq!(counting.join().unwrap().unwrap().1, CacheStatus::Miss);
+    }
+
+    #[test]
+    fn stale_statistics_are_served_while_refreshing() {
+        let repo: &str = "https://example.com/u/refreshed";
+        let key: String = format!("{}#sha", repo);
+        let statistics = |skipped: usize| Statistics {
+            skipped,
+            ..Statistics::default()
+        };
+        let mut stale = CachedStatistics::new(statistics(1));
+        stale.lifespan = Duration::ZERO;
+        CACHE.lock().unwrap().cache_set(key.clone(), stale);
+
+        let (served, status) = get_statistics(key.clone(), None, repo, None, false, move || {
+            Ok(statistics(2))
+        })
+        .unwrap();
+        assert_eq!((served.skipped, status), (1, CacheStatus::Stale));
+
+        // The refresh finishes in the background.
+        while REFRESHING.lock().unwrap().contains(&key) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let (served, status) = get_statistics(key, None, repo, None, false, || {
+            panic!("Refreshed statistics are cached.")
+        })
+        .unwrap();
+        assert_eq!((served.skipped, status), (2, CacheStatus::Hit));
+    }
+
+    #[test]
+    fn statistics_cached_together_expire_apart() {
+        let jitter: Duration = CACHE_LIFESPAN * *CACHE_JITTER_PERCENT as u32 / 100;
+        let lifespans: HashSet<Duration> = (0..10)
+            .map(|_| CachedStatistics::new(Statistics::default()).lifespan)
+            .collect();
+
+        assert!(lifespans.len() > 1);
+        for lifespan in lifespans {
+            assert!(lifespan >= CACHE_LIFESPAN - jitter);
+            assert!(lifespan <= CACHE_LIFESPAN + jitter);
+        }
+    }
+
+    #[test]
+    fn large_statistics_evict_small_ones() {
+        let statistics = |files: usize| -> Statistics {
+            let mut language = Language::new();
+            for i in 0..files {
+                language.add_report(tokei::Report::new(PathBuf::from(format!("{}.rs", i))));
+            }
+            Statistics {
+                languages: vec![(LanguageType::Rust, language)],
+                ..Statistics::default()
+            }
+        };
+        let mut cache: SizedCache<String, CachedStatistics> = SizedCache::with_size(100);
+        for key in ["a", "b", "c", "d"] {
+            cache.cache_set(key.to_owned(), CachedStatistics::new(statistics(10)));
+        }
+        let small: usize = cache.cache_get("a").unwrap().size;
+        let max_bytes: usize = small * 5;
+        evict_to_fit(&mut cache, max_bytes);
+        assert_eq!(cache.cache_size(), 4);
+
+        // Only the most recently used small statistics still fit alongside.
+        cache.cache_set("large".to_owned(), CachedStatistics::new(statistics(35)));
+        let large: usize = cache.cache_get("large").unwrap().size;
+        assert!(large > small * 3 && large + small * 2 > max_bytes);
+        evict_to_fit(&mut cache, max_bytes);
+        let keys: Vec<&String> = cache.key_order().collect();
+        assert_eq!(keys, ["large", "a"]);
+
+        // Statistics larger than the whole cache are still kept on their own.
+        cache.cache_set("huge".to_owned(), CachedStatistics::new(statistics(100)));
+        evict_to_fit(&mut cache, max_bytes);
+        let keys: Vec<&String> = cache.key_order().collect();
+        assert_eq!(keys, ["huge"]);
+    }
+
+    #[test]
+    fn sweeping_removes_expired_entries() {
+        let cached = |age: u64| CachedStatistics {
+            counted_at: Instant::now() - Duration::from_secs(age),
+            lifespan: Duration::from_secs(10),
+            ..CachedStatistics::new(Statistics::default())
+        };
+        {
+            let mut cache = CACHE.lock().unwrap();
+            cache.cache_set("sweep#expired".to_owned(), cached(25));
+            cache.cache_set("sweep#stale".to_owned(), cached(15));
+            cache.cache_set("sweep#fresh".to_owned(), cached(0));
+        }
+        sweep_caches();
+        {
+            let mut cache = CACHE.lock().unwrap();
+            assert!(cache.cache_get("sweep#expired").is_none());
+            assert!(cache.cache_get("sweep#stale").is_some());
+            assert!(cache.cache_get("sweep#fresh").is_some());
+        }
+
+        let resolved = Mutex::new(cached::TimedSizedCache::with_size_and_lifespan(10, 1));
+        resolved
+            .lock()
+            .unwrap()
+            .cache_set("sweep".to_owned(), "sha".to_owned());
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(flush(&resolved), 1);
+        assert_eq!(resolved.lock().unwrap().cache_size(), 0);
+    }
+}
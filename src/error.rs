@@ -0,0 +1,191 @@
+use actix_web::{
+    http::header::{ContentType, RETRY_AFTER},
+    http::StatusCode,
+    HttpResponse,
+};
+
+use crate::{GitHubApiError, TMPDIR};
+
+#[derive(Debug)]
+pub(crate) enum StorageError {
+    /// The temporary directory doesn't exist or can't be inspected.
+    Unavailable(std::io::Error),
+    /// The temporary directory has less free space than `TOKEI_MIN_FREE_SPACE`.
+    Insufficient { available: u64, required: u64 },
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Unavailable(error) => write!(
+                f,
+                "Temporary directory {} is unavailable: {}",
+                TMPDIR.display(),
+                error
+            ),
+            StorageError::Insufficient {
+                available,
+                required,
+            } => write!(
+                f,
+                "Not enough free space to clone, {} bytes available of the {} required.",
+                available, required
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Converts an error from counting a repository into a response, which is
+/// the client's fault unless the server ran out of room to clone it.
+pub(crate) fn counting_error(error: eyre::Report) -> actix_web::Error {
+    let error: eyre::Report = match error.downcast::<CodedError>() {
+        Ok(coded_error) => return coded_error.into(),
+        Err(error) => error,
+    };
+
+    let (code, status) = if let Some(github_api_error) = error.downcast_ref::<GitHubApiError>() {
+        match github_api_error {
+            GitHubApiError::Disabled => (ErrorCode::ApiDisabled, StatusCode::NOT_FOUND),
+            GitHubApiError::RateLimited { .. } => {
+                (ErrorCode::RateLimited, StatusCode::TOO_MANY_REQUESTS)
+            }
+        }
+    } else {
+        match error.downcast_ref::<StorageError>() {
+            Some(StorageError::Insufficient { .. }) => (
+                ErrorCode::InsufficientStorage,
+                StatusCode::INSUFFICIENT_STORAGE,
+            ),
+            Some(StorageError::Unavailable(_)) => {
+                (ErrorCode::Internal, StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            None => (ErrorCode::CountFailed, StatusCode::BAD_REQUEST),
+        }
+    };
+
+    CodedError::new(code, status, error).into()
+}
+
+/// Why a request failed, as a code that programs can check, unlike the
+/// message that goes with it, which may change.
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum ErrorCode {
+    /// A query parameter or path segment isn't valid.
+    InvalidParameter,
+    /// The requested branch, or any fallback, doesn't exist.
+    InvalidBranch,
+    /// The repository couldn't be reached, or doesn't exist.
+    RepoNotFound,
+    /// The repository has more files or bytes than this server counts.
+    TooLarge,
+    /// The option requires a feature that's disabled on this server.
+    FeatureDisabled,
+    /// `?source=api` isn't enabled on this server.
+    ApiDisabled,
+    /// `TOKEI_URL_SECRET` is set, and the request's `sig` is missing or
+    /// doesn't match.
+    InvalidSignature,
+    RateLimited,
+    InsufficientStorage,
+    /// Too many counts of the repository are running already, so the request
+    /// should be retried after its `Retry-After`.
+    Busy,
+    /// Cloning or counting the repository failed.
+    CountFailed,
+    Internal,
+    /// Errors from outside of tokei.rs, such as unknown paths, which are
+    /// only coded by their status.
+    BadRequest,
+    NotFound,
+}
+
+impl ErrorCode {
+    pub(crate) fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            status if status.is_server_error() => ErrorCode::Internal,
+            _ => ErrorCode::BadRequest,
+        }
+    }
+}
+
+/// An error with an `ErrorCode`, which is returned as JSON to requests for
+/// JSON, and as its message otherwise.
+#[derive(Debug)]
+pub(crate) struct CodedError {
+    pub(crate) code: ErrorCode,
+    pub(crate) status: StatusCode,
+    pub(crate) message: String,
+    pub(crate) retry_after: Option<u64>,
+}
+
+impl CodedError {
+    pub(crate) fn new(
+        code: ErrorCode,
+        status: StatusCode,
+        message: impl std::fmt::Display,
+    ) -> Self {
+        CodedError {
+            code,
+            status,
+            message: message.to_string(),
+            retry_after: None,
+        }
+    }
+
+    /// Sends a `Retry-After` header of `seconds` with the error.
+    pub(crate) fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+}
+
+impl std::fmt::Display for CodedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+impl actix_web::ResponseError for CodedError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if let Some(retry_after) = self.retry_after {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+        response
+            .content_type(ContentType::plaintext())
+            .body(self.message.clone())
+    }
+}
+
+pub(crate) fn bad_request(code: ErrorCode, message: impl std::fmt::Display) -> actix_web::Error {
+    CodedError::new(code, StatusCode::BAD_REQUEST, message).into()
+}
+
+/// A repository being larger than this server counts, which is the
+/// client's fault like other counting errors.
+pub(crate) fn too_large(message: String) -> eyre::Report {
+    CodedError::new(ErrorCode::TooLarge, StatusCode::BAD_REQUEST, message).into()
+}
+
+/// The body of an error returned as JSON.
+#[derive(serde::Serialize)]
+pub(crate) struct ErrorBody<'a> {
+    pub(crate) error: ErrorDetails<'a>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct ErrorDetails<'a> {
+    pub(crate) code: ErrorCode,
+    pub(crate) message: &'a str,
+}
@@ -0,0 +1,547 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use once_cell::sync::Lazy;
+use tempfile::TempDir;
+
+use crate::error::{bad_request, counting_error, too_large, ErrorCode};
+use crate::{
+    cache_url, check_free_space, directory_size, env_var, redact_url, DETACHED_HEAD, HASH_LENGTH,
+    TMPDIR,
+};
+
+/// How long the refs listed by cloning a repository are kept, as the
+/// repositories that need it would otherwise be cloned twice for each badge.
+const CLONED_REFS_LIFESPAN_SECS: u64 = 5 * 60;
+const DEFAULT_BRANCHES: [&str; 2] = ["main", "master"];
+static REFERENCE_REPOS: Lazy<Vec<(String, PathBuf)>> = Lazy::new(|| {
+    std::env::var("TOKEI_REFERENCE_REPOS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|reference| reference.split_once('='))
+        .map(|(prefix, path)| {
+            (
+                prefix.trim().trim_matches('/').to_lowercase(),
+                PathBuf::from(path.trim()),
+            )
+        })
+        .filter(|(prefix, _)| !prefix.is_empty())
+        .collect()
+});
+static GIT_PROTOCOL_FALLBACK: Lazy<bool> =
+    Lazy::new(|| env_var("TOKEI_GIT_PROTOCOL_FALLBACK").unwrap_or(false));
+static SSH_KEY: Lazy<Option<PathBuf>> = Lazy::new(|| env_var("TOKEI_SSH_KEY"));
+static GIT_SSL_VERSION: Lazy<Option<String>> = Lazy::new(|| env_var("TOKEI_GIT_SSL_VERSION"));
+static GIT_CAINFO: Lazy<Option<PathBuf>> = Lazy::new(|| env_var("TOKEI_GIT_CAINFO"));
+static MAX_MIRROR_SIZE: Lazy<u64> =
+    Lazy::new(|| env_var("TOKEI_MAX_MIRROR_SIZE").unwrap_or(1024 * 1024 * 1024));
+
+/// Lists the refs of `url`, returning them along with the URL they were
+/// listed from, which may use a fallback protocol if `https://` failed, or be
+/// where the repository was redirected to if it has moved. If the remote
+/// doesn't list any, it's cloned to list them when `clone_if_empty` is set.
+pub(crate) fn ls_remote(url: &str, clone_if_empty: bool) -> actix_web::Result<(String, String)> {
+    ls_remotes(url, remote_urls(url), clone_if_empty)
+}
+
+/// [`ls_remote`], trying each of `remotes` for `url` in turn.
+pub(crate) fn ls_remotes(
+    url: &str,
+    remotes: Vec<String>,
+    clone_if_empty: bool,
+) -> actix_web::Result<(String, String)> {
+    for remote in remotes {
+        let (mut git, bare_remote) = git_remote(&remote);
+        let ls_remote: Output = git
+            .args([
+                "ls-remote",
+                "--symref",
+                &bare_remote,
+                "HEAD",
+                "refs/heads/**",
+            ])
+            .output()?;
+
+        if !ls_remote.status.success() {
+            log::info!(
+                "{} - Couldn't list refs: {}",
+                redact_url(&remote),
+                String::from_utf8_lossy(&ls_remote.stderr).trim()
+            );
+            continue;
+        }
+
+        let mut output: String = String::from_utf8(ls_remote.stdout).map_err(|_| {
+            bad_request(
+                ErrorCode::RepoNotFound,
+                eyre::eyre!("Invalid SHA provided."),
+            )
+        })?;
+        if output.trim().is_empty() && clone_if_empty {
+            output = list_refs_by_cloning(&remote)?;
+        }
+        let stderr = String::from_utf8_lossy(&ls_remote.stderr);
+        if let Some(redirect) = redirected_url(&remote, &stderr) {
+            log::info!("{} - Redirected to {}", redact_url(&remote), redirect);
+            return Ok((redirect, output));
+        }
+        return Ok((remote, output));
+    }
+
+    Err(bad_request(
+        ErrorCode::RepoNotFound,
+        eyre::eyre!("Couldn't list the refs of {}.", redact_url(url)),
+    ))
+}
+
+/// Lists the refs of `remote` from a bare clone of it, for servers that only
+/// speak git's "dumb" HTTP protocol and don't list any refs of their own. An
+/// empty repository still lists nothing.
+#[cached::proc_macro::cached(
+    name = "CLONED_REFS_CACHE",
+    result = true,
+    type = "cached::TimedSizedCache<String, String>",
+    create = "{ cached::TimedSizedCache::with_size_and_lifespan(1000, CLONED_REFS_LIFESPAN_SECS) }",
+    convert = r#"{ cache_url(remote) }"#
+)]
+pub(crate) fn list_refs_by_cloning(remote: &str) -> actix_web::Result<String> {
+    check_free_space().map_err(|error| counting_error(error.into()))?;
+
+    log::info!(
+        "{} - Listed no refs, cloning to list them",
+        redact_url(remote)
+    );
+    let temp_dir: TempDir = TempDir::new_in(&*TMPDIR)?;
+    let temp_path: &str = temp_dir.path().to_str().unwrap();
+    let clone: Output = git_clone(remote, temp_dir.path(), &["--bare"])?;
+    if !clone.status.success() {
+        return Ok(String::new());
+    }
+
+    let ls_remote: Output = git()
+        .args(["ls-remote", "--symref", temp_path, "HEAD", "refs/heads/**"])
+        .output()?;
+    Ok(String::from_utf8_lossy(&ls_remote.stdout).into_owned())
+}
+
+/// Where git says it followed `remote` to, from the `redirecting to` warning
+/// it prints when a repository has moved.
+fn redirected_url(remote: &str, stderr: &str) -> Option<String> {
+    let redirect: &str = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("warning: redirecting to "))?
+        .trim()
+        .trim_end_matches('/');
+
+    (redirect != remote).then(|| redirect.to_owned())
+}
+
+/// The URL statistics for `url` are cached and logged under. That's the URL a
+/// moved repository was redirected to, so it's only cached once, but not a
+/// fallback protocol's URL.
+pub(crate) fn canonical_url(url: &str, remote: &str) -> String {
+    if !remote.starts_with("https://") {
+        return url.to_owned();
+    }
+    let remote: String = redact_url(remote);
+
+    // Redirects add `.git`, which would otherwise cache the repository under
+    // two URLs, so it's only kept if it was requested.
+    if url.ends_with(".git") {
+        remote
+    } else {
+        remote.strip_suffix(".git").unwrap_or(&remote).to_owned()
+    }
+}
+
+/// The URLs to try for the `https://` URL `url`, in order. `git://` is only
+/// tried if `TOKEI_GIT_PROTOCOL_FALLBACK` is set, and SSH only if
+/// `TOKEI_SSH_KEY` is.
+fn remote_urls(url: &str) -> Vec<String> {
+    remote_urls_with(url, *GIT_PROTOCOL_FALLBACK, SSH_KEY.is_some())
+}
+
+/// The URLs to try for `url`. Only `url` itself keeps its credentials, the
+/// fallbacks authenticate with `TOKEI_SSH_KEY` or not at all, and would
+/// otherwise put the credentials in git's arguments and the logs.
+pub(crate) fn remote_urls_with(url: &str, git_protocol: bool, ssh: bool) -> Vec<String> {
+    let mut remotes: Vec<String> = vec![url.to_owned()];
+    let bare_url: String = redact_url(url);
+    let Some(path) = bare_url.strip_prefix("https://") else {
+        return remotes;
+    };
+
+    if git_protocol {
+        remotes.push(format!("git://{}", path));
+    }
+    if ssh {
+        if let Some((host, repo)) = path.split_once('/') {
+            let repo: &str = repo.trim_end_matches(".git");
+            remotes.push(format!("git@{}:{}.git", host, repo));
+        }
+    }
+
+    remotes
+}
+
+/// A `git` command for `url`, along with `url` without the credentials in it.
+/// Rather than being passed in the URL, where they'd show up in the arguments
+/// of git's processes, e.g. in `ps`, and in the `origin` of clones, the
+/// credentials are passed in the environment to a credential helper for the
+/// URL's host.
+pub(crate) fn git_remote(url: &str) -> (Command, String) {
+    const CREDENTIAL_HELPER: &str = r#"!f() { test "$1" = get && printf 'username=%s\npassword=%s\n' "$TOKEI_CREDENTIAL_USERNAME" "$TOKEI_CREDENTIAL_PASSWORD"; }; f"#;
+
+    let mut git: Command = git();
+    let Some((scheme, location)) = url.split_once("://") else {
+        return (git, url.to_owned());
+    };
+    let Some((userinfo, location)) = location
+        .split_once('@')
+        .filter(|(userinfo, _)| !userinfo.contains('/'))
+    else {
+        return (git, url.to_owned());
+    };
+
+    let (user, password): (&str, &str) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+    let decode = |value: &str| {
+        percent_encoding::percent_decode_str(value)
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    let host: &str = location.split('/').next().unwrap_or_default();
+    let context: String = format!("credential.{}://{}", scheme, host);
+    // An empty helper comes first, so that helpers configured elsewhere
+    // aren't asked before this one.
+    git.arg("-c")
+        .arg(format!("{}.helper=", context))
+        .arg("-c")
+        .arg(format!("{}.helper={}", context, CREDENTIAL_HELPER))
+        .env("TOKEI_CREDENTIAL_USERNAME", decode(user))
+        .env("TOKEI_CREDENTIAL_PASSWORD", decode(password));
+
+    (git, format!("{}://{}", scheme, location))
+}
+
+/// A `git` command, which authenticates SSH remotes with `TOKEI_SSH_KEY` and
+/// enforces the TLS settings from `TOKEI_GIT_SSL_VERSION` and
+/// `TOKEI_GIT_CAINFO`. Config set with `-c` also applies to the git commands
+/// it runs, such as when cloning submodules.
+pub(crate) fn git() -> Command {
+    git_with(
+        GIT_SSL_VERSION.as_deref(),
+        GIT_CAINFO.as_deref(),
+        SSH_KEY.as_deref(),
+    )
+}
+
+/// A `git` command enforcing `ssl_version` and `cainfo`, and authenticating
+/// SSH remotes with `ssh_key`.
+pub(crate) fn git_with(
+    ssl_version: Option<&str>,
+    cainfo: Option<&Path>,
+    ssh_key: Option<&Path>,
+) -> Command {
+    let mut git = Command::new("git");
+    if let Some(ssl_version) = ssl_version {
+        git.arg("-c")
+            .arg(format!("http.sslVersion={}", ssl_version));
+    }
+    if let Some(cainfo) = cainfo {
+        git.arg("-c")
+            .arg(format!("http.sslCAInfo={}", cainfo.display()));
+    }
+    if let Some(ssh_key) = ssh_key {
+        git.env(
+            "GIT_SSH_COMMAND",
+            format!(
+                "ssh -i '{}' -o IdentitiesOnly=yes -o BatchMode=yes -o StrictHostKeyChecking=accept-new",
+                ssh_key.display()
+            ),
+        );
+    }
+    git
+}
+
+/// The refs advertised by a remote, as reported by `git ls-remote --symref`.
+#[derive(Debug, Default, serde::Serialize)]
+pub(crate) struct Refs {
+    /// The branch or tag the remote's `HEAD` points to.
+    pub(crate) head: Option<String>,
+    /// The SHA the remote's `HEAD` points to, which is the only thing
+    /// reported when `HEAD` is detached.
+    pub(crate) head_sha: Option<String>,
+    /// Branch names mapped to the SHA they point to.
+    pub(crate) branches: BTreeMap<String, String>,
+}
+
+pub(crate) fn parse_refs(ls_remote_output: &str) -> Refs {
+    let mut refs = Refs::default();
+
+    for line in ls_remote_output.lines() {
+        if let Some(symref) = line.strip_prefix("ref: ") {
+            // `HEAD` can point at a tag, or at something that isn't a ref at
+            // all, in which case it's treated as detached.
+            if let Some(target) = symref.strip_suffix("\tHEAD") {
+                refs.head = target
+                    .strip_prefix("refs/heads/")
+                    .or_else(|| target.strip_prefix("refs/tags/"))
+                    .map(str::to_owned);
+            }
+        } else if let Some(sha) = line.strip_suffix("\tHEAD") {
+            refs.head_sha = Some(sha.to_owned());
+        } else if let Some((sha, branch)) = line.split_once("\trefs/heads/") {
+            refs.branches.insert(branch.to_owned(), sha.to_owned());
+        }
+    }
+
+    refs
+}
+
+/// Resolves the branch to count and the SHA it points to from the output of
+/// `git ls-remote --symref`. When no branch is requested the branch `HEAD`
+/// points to is used, or `HEAD` itself if it is detached.
+pub(crate) fn resolve_sha(
+    ls_remote_output: &str,
+    requested_branch: Option<&str>,
+) -> eyre::Result<(String, String)> {
+    let refs: Refs = parse_refs(ls_remote_output);
+
+    let (branch, sha) = match requested_branch.filter(|branch| !branch.is_empty()) {
+        Some(branch) => (branch.to_owned(), refs.branches.get(branch).cloned()),
+        None => match (refs.head, refs.head_sha) {
+            (Some(head), head_sha) => {
+                // Only branches are listed, so a tag's SHA comes from `HEAD`.
+                let sha = refs.branches.get(&head).cloned().or(head_sha);
+                (head, sha)
+            }
+            // Not every server reports the symref, so fall back to a branch
+            // that points to the same commit as `HEAD`.
+            (None, Some(sha)) => match head_branch_by_sha(&refs.branches, &sha) {
+                Some(head) => (head.to_owned(), Some(sha)),
+                None => (DETACHED_HEAD.to_owned(), Some(sha)),
+            },
+            (None, None) => eyre::bail!("Repository has no HEAD, it may be empty."),
+        },
+    };
+
+    let sha: String = sha.ok_or_else(|| eyre::eyre!("Branch `{}` not found.", branch))?;
+    if sha.len() != HASH_LENGTH {
+        eyre::bail!("Invalid SHA provided.");
+    }
+
+    Ok((branch, sha))
+}
+
+/// Clones every ref of `url` into a new temporary directory as a bare
+/// repository, for history that's read without a working tree. Mirrors can
+/// be large, so they're limited to `TOKEI_MAX_MIRROR_SIZE`.
+pub(crate) fn clone_mirror(url: &str) -> eyre::Result<TempDir> {
+    check_free_space()?;
+
+    log::info!("{} - Cloning mirror", redact_url(url));
+    let temp_dir: TempDir = TempDir::new_in(&*TMPDIR)?;
+    if !git_clone(url, temp_dir.path(), &["--mirror"])?
+        .status
+        .success()
+    {
+        eyre::bail!("Couldn't clone {}.", redact_url(url));
+    }
+    if directory_size(temp_dir.path())? > *MAX_MIRROR_SIZE {
+        return Err(too_large(format!(
+            "Repository is larger than {} bytes.",
+            *MAX_MIRROR_SIZE
+        )));
+    }
+
+    Ok(temp_dir)
+}
+
+/// Clones the history of `branch_name`, without checking it out. The
+/// contents of files are only fetched once they're needed.
+pub(crate) fn clone_history(url: &str, branch_name: &str) -> eyre::Result<TempDir> {
+    check_free_space()?;
+
+    log::info!("{} - Cloning history", redact_url(url));
+    let temp_dir: TempDir = TempDir::new_in(&*TMPDIR)?;
+    let mut args: Vec<&str> = vec!["--filter=blob:none", "--no-checkout"];
+    if branch_name != DETACHED_HEAD {
+        args.extend(["--branch", branch_name]);
+    }
+    if !git_clone(url, temp_dir.path(), &args)?.status.success() {
+        eyre::bail!("Couldn't clone {}.", redact_url(url));
+    }
+
+    Ok(temp_dir)
+}
+
+/// Shallowly clones `branch_name` of `url` into a new temporary directory.
+pub(crate) fn clone(url: &str, branch_name: &str) -> eyre::Result<TempDir> {
+    check_free_space()?;
+
+    log::info!("{} - Cloning", redact_url(url));
+    let temp_dir: TempDir = TempDir::new_in(&*TMPDIR)?;
+
+    let mut args: Vec<&str> = vec!["--depth", "1"];
+    // A detached `HEAD` isn't a branch, but it's what `git clone` checks out
+    // by default.
+    if branch_name != DETACHED_HEAD {
+        args.extend(["--branch", branch_name]);
+    }
+    let mut output: Output = git_clone(url, temp_dir.path(), &args)?;
+    // Servers that only speak git's "dumb" HTTP protocol can't clone
+    // shallowly, so the whole history is cloned from them instead.
+    if !output.status.success()
+        && String::from_utf8_lossy(&output.stderr).contains("does not support shallow")
+    {
+        log::info!(
+            "{} - Can't clone shallowly, cloning history",
+            redact_url(url)
+        );
+        output = git_clone(url, temp_dir.path(), &args[2..])?;
+    }
+    if !output.status.success() {
+        eyre::bail!("Couldn't clone {}.", redact_url(url));
+    }
+
+    Ok(temp_dir)
+}
+
+/// Runs `git clone` of `url` into `path`, borrowing objects from the
+/// repository's reference clone if it has one. If cloning with the reference
+/// fails, it's retried without it.
+pub(crate) fn git_clone(url: &str, path: &Path, args: &[&str]) -> std::io::Result<Output> {
+    git_clone_with(url, path, args, reference_repo(url))
+}
+
+/// [`git_clone`], borrowing objects from `reference` if it's given.
+pub(crate) fn git_clone_with(
+    url: &str,
+    path: &Path,
+    args: &[&str],
+    reference: Option<&Path>,
+) -> std::io::Result<Output> {
+    if let Some(reference) = reference {
+        let (mut git, bare_url) = git_remote(url);
+        let output: Output = git
+            .arg("clone")
+            .arg("--reference")
+            .arg(reference)
+            .args(args)
+            .arg(bare_url)
+            .arg(path)
+            .output()?;
+        if output.status.success() {
+            return Ok(output);
+        }
+        log::warn!(
+            "{} - Couldn't clone with reference {}, cloning without it",
+            redact_url(url),
+            reference.display()
+        );
+    }
+
+    let (mut git, bare_url) = git_remote(url);
+    git.arg("clone").args(args).arg(bare_url).arg(path).output()
+}
+
+/// The reference clone from `TOKEI_REFERENCE_REPOS` with the longest prefix
+/// of `url`, if it exists and is a git repository.
+fn reference_repo(url: &str) -> Option<&'static Path> {
+    reference_repo_in(url, &REFERENCE_REPOS)
+}
+
+/// The reference clone in `references`, which are pairs of URL prefixes and
+/// paths, for `url`.
+pub(crate) fn reference_repo_in<'a>(
+    url: &str,
+    references: &'a [(String, PathBuf)],
+) -> Option<&'a Path> {
+    let location: String = url
+        .split_once("://")
+        .map_or(url, |(_, location)| location)
+        .to_lowercase();
+    let (_, reference): &(String, PathBuf) = references
+        .iter()
+        .filter(|(prefix, _)| {
+            location
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())?;
+
+    let is_repository: bool = git()
+        .arg("-C")
+        .arg(reference)
+        .args(["rev-parse", "--git-dir"])
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !is_repository {
+        log::warn!(
+            "{} - Reference {} isn't a git repository, ignoring it",
+            redact_url(url),
+            reference.display()
+        );
+        return None;
+    }
+
+    Some(reference)
+}
+
+/// Lists the paths of the regular files in the tree of `sha` in the
+/// repository at `path`, which doesn't need to have a working tree. Symlinks
+/// and submodules are left out, like they are from a checkout.
+pub(crate) fn tree_files(path: &str, sha: &str) -> eyre::Result<Vec<PathBuf>> {
+    let ls_tree: Output = git()
+        .args(["-C", path, "ls-tree", "-r", "-z", sha])
+        .output()?;
+
+    if !ls_tree.status.success() {
+        eyre::bail!("Couldn't list the files of {}.", sha);
+    }
+
+    Ok(String::from_utf8(ls_tree.stdout)?
+        .split_terminator('\0')
+        .filter_map(|entry| {
+            // Each entry is `<mode> <type> <object>\t<path>`.
+            let (info, file) = entry.split_once('\t')?;
+            let mut info = info.split(' ');
+            let (mode, object_type) = (info.next()?, info.next()?);
+            (object_type == "blob" && mode != "120000").then(|| PathBuf::from(file))
+        })
+        .collect())
+}
+
+/// Lists the paths of every file tracked by git in the checkout at `path`,
+/// including those in any submodules that have been cloned.
+pub(crate) fn tracked_files(path: &str) -> eyre::Result<Vec<String>> {
+    let ls_files: Output = git()
+        .args(["-C", path, "ls-files", "-z", "--recurse-submodules"])
+        .output()?;
+
+    if !ls_files.status.success() {
+        eyre::bail!("Couldn't list tracked files.");
+    }
+
+    Ok(String::from_utf8(ls_files.stdout)?
+        .split_terminator('\0')
+        .map(|file| format!("{}/{}", path, file))
+        .collect())
+}
+
+/// Finds the branch pointing to `sha`, preferring the usual default branch
+/// names when several do.
+fn head_branch_by_sha<'a>(branches: &'a BTreeMap<String, String>, sha: &str) -> Option<&'a str> {
+    let matching: Vec<&str> = branches
+        .iter()
+        .filter(|(_, branch_sha)| *branch_sha == sha)
+        .map(|(branch, _)| branch.as_str())
+        .collect();
+
+    matching
+        .iter()
+        .find(|branch| DEFAULT_BRANCHES.contains(branch))
+        .or_else(|| matching.first())
+        .copied()
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use actix_web::{http::header::ContentType, HttpRequest};
+use once_cell::sync::Lazy;
+use sha2::Digest;
+
+const OBJECT_STORE_TIMEOUT_SECS: u64 = 5;
+
+pub(crate) static OBJECT_STORE: Lazy<Option<ObjectStore>> = Lazy::new(|| {
+    Some(ObjectStore::new(
+        &std::env::var("TOKEI_OBJECT_STORE_URL").ok()?,
+        std::env::var("TOKEI_OBJECT_STORE_TOKEN").ok(),
+    ))
+});
+
+/// The name rendered badges for the statistics cached under `key` are stored
+/// under in `TOKEI_OBJECT_STORE_URL`, which covers every option of the
+/// request, as they all change the badge. It's hashed with SHA-256 rather
+/// than Rust's own hasher, which may change between builds, so that every
+/// instance stores the same badge under the same name.
+pub(crate) fn object_key(key: &str, request: &HttpRequest, content_type: &ContentType) -> String {
+    let mut hasher = sha2::Sha256::new();
+    for part in [key, request.query_string(), &content_type.to_string()] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    let extension: &str = if *content_type == ContentType::json() {
+        "json"
+    } else {
+        "svg"
+    };
+
+    format!("{:x}.{}", hasher.finalize(), extension)
+}
+
+/// The object store badges are shared between instances through.
+pub(crate) struct ObjectStore {
+    url: String,
+    token: Option<String>,
+}
+
+impl ObjectStore {
+    pub(crate) fn new(url: &str, token: Option<String>) -> Self {
+        ObjectStore {
+            url: url.trim_end_matches('/').to_owned(),
+            token,
+        }
+    }
+
+    fn request(&self, method: &str, key: &str) -> ureq::Request {
+        let url: String = format!("{}/{}", self.url, key);
+        let request: ureq::Request =
+            ureq::request(method, &url).timeout(Duration::from_secs(OBJECT_STORE_TIMEOUT_SECS));
+
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    /// The object stored under `key`. The object store being unavailable is
+    /// treated like the object not being there.
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        match self.request("GET", key).call() {
+            Ok(response) => response.into_string().ok(),
+            Err(ureq::Error::Status(404, _)) => None,
+            Err(error) => {
+                log::warn!("{} - Couldn't fetch from the object store: {}", key, error);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn put(&self, key: &str, body: &str) {
+        if let Err(error) = self.request("PUT", key).send_string(body) {
+            log::warn!("{} - Couldn't write to the object store: {}", key, error);
+        }
+    }
+}
+
+/// Fetches a badge another instance rendered from the object store.
+pub(crate) fn fetch_object(key: &str) -> Option<String> {
+    OBJECT_STORE.as_ref()?.get(key)
+}
+
+/// Stores a rendered badge in the object store on a background thread, so
+/// that the response doesn't wait for it.
+pub(crate) fn store_object(key: String, body: String) {
+    let Some(object_store) = OBJECT_STORE.as_ref() else {
+        return;
+    };
+
+    std::thread::spawn(move || object_store.put(&key, &body));
+}